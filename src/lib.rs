@@ -0,0 +1,504 @@
+use curve25519_dalek::constants::BASEPOINT_ORDER;
+use curve25519_dalek::scalar::Scalar;
+use libspartan::{Instance, InputsAssignment, VarsAssignment, NIZKGens, SNARKGens};
+use merlin::Transcript;
+use zkinterface::{CircuitHeaderOwned, ConstraintOwned, ConstraintSystemOwned, WitnessOwned, VariablesOwned};
+use zkinterface::flatbuffers::root;
+use zkinterface_generated::zkinterface::{CircuitHeader, ConstraintSystem, Witness};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use bincode;
+
+// The CircuitHeader, ConstraintSystem, and (when proving) Witness messages
+// that describe one R1CS instance
+pub struct R1csReader {
+    header: CircuitHeaderOwned,
+    constraints: ConstraintSystemOwned,
+    witness: Option<WitnessOwned>,
+}
+
+// Splits a stream into length-prefixed (u32 LE) messages; falls back to
+// treating the whole buffer as one unframed message if it isn't framed
+fn messages(buf: &[u8]) -> Vec<&[u8]> {
+    if buf.is_empty() {
+        return Vec::new();
+    }
+    let mut framed = Vec::new();
+    let mut offset = 0;
+    loop {
+        if offset == buf.len() {
+            return framed;
+        }
+        if offset + 4 > buf.len() {
+            break;
+        }
+        let len = u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+        let start = offset + 4;
+        if start + len > buf.len() {
+            break;
+        }
+        framed.push(&buf[start..start + len]);
+        offset = start + len;
+    }
+    vec![buf]
+}
+
+impl R1csReader {
+    // Reads a circuit together with its witness, for proving
+    pub fn new(inputs_buf: &mut Vec<u8>, circuit_buf: &mut Vec<u8>, witness_buf: &mut Vec<u8>) -> Result<Self, String> {
+        let header = Self::read_header(inputs_buf)?;
+        let constraints = Self::read_constraints(circuit_buf)?;
+        let witness = Self::read_witness(witness_buf)?;
+        Ok(R1csReader { header, constraints, witness: Some(witness) })
+    }
+
+    // Reads only the public structure of a circuit, for verification
+    pub fn new_public(inputs_buf: &mut Vec<u8>, circuit_buf: &mut Vec<u8>) -> Result<Self, String> {
+        let header = Self::read_header(inputs_buf)?;
+        let constraints = Self::read_constraints(circuit_buf)?;
+        Ok(R1csReader { header, constraints, witness: None })
+    }
+
+    // Only the first CircuitHeader message is kept; later chunks repeat it
+    fn read_header(buf: &[u8]) -> Result<CircuitHeaderOwned, String> {
+        let first = messages(buf)
+            .into_iter()
+            .next()
+            .ok_or_else(|| "no CircuitHeader message found in inputs stream".to_string())?;
+        let header =
+            root::<CircuitHeader>(first).map_err(|e| format!("corrupt CircuitHeader message: {:?}", e))?;
+        Ok(CircuitHeaderOwned::from(header))
+    }
+
+    fn read_constraints(buf: &[u8]) -> Result<ConstraintSystemOwned, String> {
+        let mut merged = ConstraintSystemOwned { constraints: Vec::new() };
+        for m in messages(buf) {
+            let cs = root::<ConstraintSystem>(m)
+                .map_err(|e| format!("corrupt ConstraintSystem message: {:?}", e))?;
+            merged.constraints.extend(ConstraintSystemOwned::from(cs).constraints);
+        }
+        Ok(merged)
+    }
+
+    fn read_witness(buf: &[u8]) -> Result<WitnessOwned, String> {
+        let mut merged = WitnessOwned {
+            assigned_variables: VariablesOwned { variable_ids: Vec::new(), values: Vec::new() },
+        };
+        for m in messages(buf) {
+            let w = root::<Witness>(m).map_err(|e| format!("corrupt Witness message: {:?}", e))?;
+            let chunk = WitnessOwned::from(w);
+            merged.assigned_variables.variable_ids.extend(chunk.assigned_variables.variable_ids);
+            merged.assigned_variables.values.extend(chunk.assigned_variables.values);
+        }
+        Ok(merged)
+    }
+}
+
+// Witness variables occupy columns 0..num_vars, the constant 1 occupies
+// num_vars, and public inputs occupy num_vars+1..num_vars+1+num_inputs
+pub struct R1cs {
+    num_cons: usize,
+    num_vars: usize,
+    num_inputs: usize,
+    constraints: ConstraintSystemOwned,
+    columns: HashMap<u64, usize>,
+    witness: Option<WitnessOwned>,
+    inputs: VariablesOwned,
+    reduce: bool,
+}
+
+// field_maximum is the field's largest element, i.e. modulus minus one
+fn ristretto255_field_maximum() -> [u8; 32] {
+    let mut max = BASEPOINT_ORDER.to_bytes();
+    let mut borrow = 1u16;
+    for byte in max.iter_mut() {
+        let (res, b) = (*byte).overflowing_sub(borrow as u8);
+        *byte = res;
+        borrow = if b { 1 } else { 0 };
+    }
+    max
+}
+
+pub fn field_matches_ristretto(field_maximum: &[u8]) -> Result<bool, String> {
+    Ok(le_bytes(field_maximum)? == ristretto255_field_maximum())
+}
+
+// Maps id 0 to the constant column, instance_variables to the public input
+// columns, and every other id to a witness column in ascending id order
+fn derive_columns(header: &CircuitHeaderOwned, num_vars: usize) -> HashMap<u64, usize> {
+    let mut columns = HashMap::with_capacity(num_vars + header.instance_variables.variable_ids.len() + 1);
+    columns.insert(0u64, num_vars);
+    for (i, id) in header.instance_variables.variable_ids.iter().enumerate() {
+        columns.insert(*id, num_vars + 1 + i);
+    }
+    let mut next = 0;
+    for id in 1..header.free_variable_id {
+        if !columns.contains_key(&id) {
+            columns.insert(id, next);
+            next += 1;
+        }
+    }
+    columns
+}
+
+impl R1cs {
+    // Validates the circuit's field against ristretto255 unless reduce is set
+    pub fn new(r: R1csReader, reduce: bool) -> Result<Self, String> {
+        if let Some(field_maximum) = &r.header.field_maximum {
+            if !reduce && !field_matches_ristretto(field_maximum)? {
+                return Err(
+                    "circuit field does not match Spartan's ristretto255 scalar field; \
+                     pass --reduce to reduce coefficients into it"
+                        .to_string(),
+                );
+            }
+        }
+
+        let num_inputs = r.header.instance_variables.variable_ids.len();
+        let num_cons = r.constraints.constraints.len();
+        let num_vars = (r.header.free_variable_id as usize)
+            .saturating_sub(1 + num_inputs);
+        let columns = derive_columns(&r.header, num_vars);
+
+        Ok(R1cs {
+            num_cons,
+            num_vars,
+            num_inputs,
+            constraints: r.constraints,
+            columns,
+            witness: r.witness,
+            inputs: r.header.instance_variables,
+            reduce,
+        })
+    }
+}
+
+// Pads a field element into a 32-byte little-endian scalar, rejecting
+// anything too wide to fit (e.g. a circuit built for BLS12-381)
+fn le_bytes(value: &[u8]) -> Result<[u8; 32], String> {
+    if value.len() > 32 {
+        return Err(format!(
+            "field element is {} bytes, which does not fit in a 32-byte ristretto255 scalar",
+            value.len()
+        ));
+    }
+    let mut out = [0u8; 32];
+    out[..value.len()].copy_from_slice(value);
+    Ok(out)
+}
+
+// Reduces a padded little-endian coefficient modulo the ristretto255 order
+fn reduce_le_bytes(padded: [u8; 32]) -> [u8; 32] {
+    Scalar::from_bytes_mod_order(padded).to_bytes()
+}
+
+fn push_terms(
+    columns: &HashMap<u64, usize>,
+    row: usize,
+    lc: &VariablesOwned,
+    out: &mut Vec<(usize, usize, [u8; 32])>,
+    reduce: bool,
+) -> Result<(), String> {
+    for (id, value) in lc.variable_ids.iter().zip(lc.values.iter()) {
+        let padded = le_bytes(value)?;
+        let scalar = if reduce { reduce_le_bytes(padded) } else { padded };
+        let column = *columns
+            .get(id)
+            .ok_or_else(|| format!("constraint row {} references unknown variable id {}", row, id))?;
+        out.push((row, column, scalar));
+    }
+    Ok(())
+}
+
+// Folds a sparse A/B/C matrix into a fixed 32-byte digest, cheaper to bind
+// into the transcript than the full serialized matrix
+fn digest_sparse_matrix(matrix: &[(usize, usize, [u8; 32])]) -> [u8; 32] {
+    const PRIME: u64 = 1_099_511_628_211;
+    let mut state = [0u64; 4];
+    for (row, col, scalar) in matrix {
+        state[0] = state[0].wrapping_mul(PRIME).wrapping_add(*row as u64);
+        state[1] = state[1].wrapping_mul(PRIME).wrapping_add(*col as u64);
+        for (i, chunk) in scalar.chunks(8).enumerate() {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let slot = 2 + (i % 2);
+            state[slot] = state[slot].wrapping_mul(PRIME).wrapping_add(u64::from_le_bytes(word));
+        }
+    }
+    let mut out = [0u8; 32];
+    for (i, word) in state.iter().enumerate() {
+        out[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+impl R1cs {
+    pub fn num_cons(&self) -> usize { self.num_cons }
+    pub fn num_vars(&self) -> usize { self.num_vars }
+    pub fn num_inputs(&self) -> usize { self.num_inputs }
+
+    // Builds the sparse A/B/C matrices and the Instance backed by them;
+    // needs no witness, so a verifier can call this too
+    pub fn instance(
+        &self,
+        a: &mut Vec<(usize, usize, [u8; 32])>,
+        b: &mut Vec<(usize, usize, [u8; 32])>,
+        c: &mut Vec<(usize, usize, [u8; 32])>,
+    ) -> Result<Instance, String> {
+        for (row, constraint) in self.constraints.constraints.iter().enumerate() {
+            push_terms(&self.columns, row, &constraint.linear_combination_a, a, self.reduce)?;
+            push_terms(&self.columns, row, &constraint.linear_combination_b, b, self.reduce)?;
+            push_terms(&self.columns, row, &constraint.linear_combination_c, c, self.reduce)?;
+        }
+
+        Instance::new(self.num_cons, self.num_vars, self.num_inputs, a, b, c)
+            .map_err(|e| format!("zkinterface circuit produced a malformed R1CS instance: {:?}", e))
+    }
+
+    // Requires the witness this R1cs was built from; panics for new_public readers
+    pub fn vars_assignment(&self) -> Result<VarsAssignment, String> {
+        let witness = self
+            .witness
+            .as_ref()
+            .expect("a witness is required to build a proof, not just to verify one");
+        let mut assignment = vec![[0u8; 32]; self.num_vars];
+        for (id, value) in witness
+            .assigned_variables
+            .variable_ids
+            .iter()
+            .zip(witness.assigned_variables.values.iter())
+        {
+            let padded = le_bytes(value)?;
+            let column = *self
+                .columns
+                .get(id)
+                .ok_or_else(|| format!("witness assigns unknown variable id {}", id))?;
+            if column >= self.num_vars {
+                return Err(format!(
+                    "witness assigns variable id {} which is not a private witness column",
+                    id
+                ));
+            }
+            assignment[column] = if self.reduce { reduce_le_bytes(padded) } else { padded };
+        }
+        VarsAssignment::new(&assignment).map_err(|e| format!("witness assignment out of range: {:?}", e))
+    }
+
+    // The public input values, in header order, as raw little-endian scalars
+    pub fn public_inputs(&self) -> Result<Vec<[u8; 32]>, String> {
+        self.inputs
+            .values
+            .iter()
+            .map(|v| {
+                let padded = le_bytes(v)?;
+                Ok(if self.reduce { reduce_le_bytes(padded) } else { padded })
+            })
+            .collect()
+    }
+
+    pub fn inputs_assignment(&self) -> Result<InputsAssignment, String> {
+        InputsAssignment::new(&self.public_inputs()?)
+            .map_err(|e| format!("public input assignment out of range: {:?}", e))
+    }
+
+    // Binds the instance shape, A/B/C matrices, SNARK commitment (if any) and
+    // public inputs into the transcript before handing it to NIZK/SNARK prove/verify
+    pub fn bind_transcript(
+        &self,
+        transcript: &mut Transcript,
+        a: &[(usize, usize, [u8; 32])],
+        b: &[(usize, usize, [u8; 32])],
+        c: &[(usize, usize, [u8; 32])],
+        inputs: &[[u8; 32]],
+        comm_bytes: Option<&[u8]>,
+        label: Option<&[u8]>,
+    ) -> Result<(), String> {
+        transcript.append_u64(b"num_cons", self.num_cons as u64);
+        transcript.append_u64(b"num_vars", self.num_vars as u64);
+        transcript.append_u64(b"num_inputs", self.num_inputs as u64);
+        transcript.append_message(b"A", &digest_sparse_matrix(a));
+        transcript.append_message(b"B", &digest_sparse_matrix(b));
+        transcript.append_message(b"C", &digest_sparse_matrix(c));
+        if let Some(comm_bytes) = comm_bytes {
+            transcript.append_message(b"comm", comm_bytes);
+        }
+        for input in inputs {
+            transcript.append_message(b"input", input);
+        }
+        if let Some(label) = label {
+            transcript.append_message(b"label", label);
+        }
+        Ok(())
+    }
+
+    pub fn nizk_public_params(&self) -> NIZKGens {
+        NIZKGens::new(self.num_cons, self.num_vars, self.num_inputs)
+    }
+
+    // SNARKGens sizes the commitment generators by the densest matrix's
+    // total non-zero entries, which is what SNARK::encode actually commits to
+    pub fn snark_public_params(&self) -> SNARKGens {
+        let (nnz_a, nnz_b, nnz_c) = self.constraints.constraints.iter().fold(
+            (0usize, 0usize, 0usize),
+            |(a, b, c), constraint| {
+                (
+                    a + constraint.linear_combination_a.variable_ids.len(),
+                    b + constraint.linear_combination_b.variable_ids.len(),
+                    c + constraint.linear_combination_c.variable_ids.len(),
+                )
+            },
+        );
+        let num_nz_entries = nnz_a.max(nnz_b).max(nnz_c);
+        SNARKGens::new(self.num_cons, self.num_vars, self.num_inputs, num_nz_entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libspartan::{NIZK, SNARK};
+
+    fn scalar_bytes(v: u64) -> Vec<u8> {
+        Scalar::from(v).to_bytes().to_vec()
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut framed = (payload.len() as u32).to_le_bytes().to_vec();
+        framed.extend_from_slice(payload);
+        framed
+    }
+
+    #[test]
+    fn messages_splits_length_prefixed_frames() {
+        let mut buf = frame(b"abc");
+        buf.extend(frame(b"xy"));
+        assert_eq!(messages(&buf), vec![&b"abc"[..], &b"xy"[..]]);
+    }
+
+    #[test]
+    fn messages_returns_each_of_several_frames_separately_in_order() {
+        let mut buf = frame(b"first-chunk");
+        buf.extend(frame(b"second-chunk"));
+        buf.extend(frame(b"third-chunk"));
+        assert_eq!(
+            messages(&buf),
+            vec![&b"first-chunk"[..], &b"second-chunk"[..], &b"third-chunk"[..]]
+        );
+    }
+
+    #[test]
+    fn messages_falls_back_to_a_single_unframed_message() {
+        let buf = b"not a u32-length-prefixed stream at all".to_vec();
+        assert_eq!(messages(&buf), vec![&buf[..]]);
+    }
+
+    #[test]
+    fn messages_of_an_empty_buffer_is_empty() {
+        assert!(messages(&[]).is_empty());
+    }
+
+    #[test]
+    fn corrupt_header_message_is_reported_not_panicked() {
+        let mut bufh = frame(b"not a flatbuffer");
+        let mut bufcs = Vec::new();
+        let mut bufw = Vec::new();
+        assert!(R1csReader::new(&mut bufh, &mut bufcs, &mut bufw).is_err());
+    }
+
+    // The single-constraint circuit x * x = y; witness selects prover vs. verifier reader
+    fn trivial_reader(x: u64, y: u64, witness: bool) -> R1csReader {
+        let header = CircuitHeaderOwned {
+            instance_variables: VariablesOwned { variable_ids: vec![2], values: vec![scalar_bytes(y)] },
+            free_variable_id: 3,
+            field_maximum: None,
+        };
+        let constraints = ConstraintSystemOwned {
+            constraints: vec![ConstraintOwned {
+                linear_combination_a: VariablesOwned { variable_ids: vec![1], values: vec![scalar_bytes(1)] },
+                linear_combination_b: VariablesOwned { variable_ids: vec![1], values: vec![scalar_bytes(1)] },
+                linear_combination_c: VariablesOwned { variable_ids: vec![2], values: vec![scalar_bytes(1)] },
+            }],
+        };
+        let witness = witness.then(|| WitnessOwned {
+            assigned_variables: VariablesOwned { variable_ids: vec![1], values: vec![scalar_bytes(x)] },
+        });
+        R1csReader { header, constraints, witness }
+    }
+
+    #[test]
+    fn nizk_prove_verify_round_trip() {
+        let prover = R1cs::new(trivial_reader(2, 4, true), false).unwrap();
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        let mut c = Vec::new();
+        let inst = prover.instance(&mut a, &mut b, &mut c).unwrap();
+        let vars = prover.vars_assignment().unwrap();
+        let inputs = prover.inputs_assignment().unwrap();
+        assert!(inst.is_sat(&vars, &inputs).unwrap());
+
+        let gens = prover.nizk_public_params();
+        let public_inputs = prover.public_inputs().unwrap();
+        let mut prover_transcript = Transcript::new(b"test_nizk");
+        prover.bind_transcript(&mut prover_transcript, &a, &b, &c, &public_inputs, None, None).unwrap();
+        let proof = NIZK::prove(&inst, vars, &inputs, &gens, &mut prover_transcript);
+
+        let verifier = R1cs::new(trivial_reader(2, 4, false), false).unwrap();
+        let mut va = Vec::new();
+        let mut vb = Vec::new();
+        let mut vc = Vec::new();
+        let v_inst = verifier.instance(&mut va, &mut vb, &mut vc).unwrap();
+        let v_inputs = verifier.inputs_assignment().unwrap();
+        let mut verifier_transcript = Transcript::new(b"test_nizk");
+        verifier
+            .bind_transcript(&mut verifier_transcript, &va, &vb, &vc, &public_inputs, None, None)
+            .unwrap();
+        assert!(proof.verify(&v_inst, &v_inputs, &mut verifier_transcript, &gens).is_ok());
+    }
+
+    #[test]
+    fn snark_prove_verify_round_trip() {
+        let prover = R1cs::new(trivial_reader(2, 4, true), false).unwrap();
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        let mut c = Vec::new();
+        let inst = prover.instance(&mut a, &mut b, &mut c).unwrap();
+        let vars = prover.vars_assignment().unwrap();
+        let inputs = prover.inputs_assignment().unwrap();
+
+        let gens = prover.snark_public_params();
+        let (comm, decomm) = SNARK::encode(&inst, &gens);
+        let comm_bytes = bincode::serialize(&comm).unwrap();
+        let public_inputs = prover.public_inputs().unwrap();
+        let mut prover_transcript = Transcript::new(b"test_snark");
+        prover
+            .bind_transcript(&mut prover_transcript, &a, &b, &c, &public_inputs, Some(&comm_bytes), None)
+            .unwrap();
+        let proof = SNARK::prove(&inst, &decomm, vars, &inputs, &gens, &mut prover_transcript);
+
+        let verifier = R1cs::new(trivial_reader(2, 4, false), false).unwrap();
+        let mut va = Vec::new();
+        let mut vb = Vec::new();
+        let mut vc = Vec::new();
+        verifier.instance(&mut va, &mut vb, &mut vc).unwrap();
+        let v_inputs = verifier.inputs_assignment().unwrap();
+        let mut verifier_transcript = Transcript::new(b"test_snark");
+        verifier
+            .bind_transcript(&mut verifier_transcript, &va, &vb, &vc, &public_inputs, Some(&comm_bytes), None)
+            .unwrap();
+        assert!(proof.verify(&comm, &v_inputs, &mut verifier_transcript, &gens).is_ok());
+    }
+
+    #[test]
+    fn mismatched_field_requires_explicit_reduce() {
+        let mut foreign_max = ristretto255_field_maximum();
+        foreign_max[0] ^= 0x01;
+
+        let mut mismatched = trivial_reader(2, 4, true);
+        mismatched.header.field_maximum = Some(foreign_max.to_vec());
+        assert!(R1cs::new(mismatched, false).is_err());
+
+        let mut reduced = trivial_reader(2, 4, true);
+        reduced.header.field_maximum = Some(foreign_max.to_vec());
+        assert!(R1cs::new(reduced, true).is_ok());
+    }
+}
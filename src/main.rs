@@ -2,80 +2,183 @@
 mod lib;
 use lib::*;
 use merlin::Transcript;
-use libspartan::{SNARKGens, SNARK, NIZK};
+use libspartan::{ComputationCommitment, InputsAssignment, SNARKGens, SNARK, NIZK};
+use serde::{Deserialize, Serialize};
 use std::env;
+use std::fmt;
 use std::format;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::string::String;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use bincode;
+use serde_json;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let nizk: bool;
-    let usage = format!(
-        "{} [prove | verify] [--nizk|--snark] <circuit.zkif> <inputs.zkif> <witness.zkif>",
-        args.get(0).unwrap()
-    );
+// Machine-readable proof metrics printed with --json
+#[derive(Serialize)]
+struct Metrics {
+    mode: &'static str,
+    circuit: String,
+    num_cons: usize,
+    num_vars: usize,
+    num_inputs: usize,
+    prover_ms: Option<u128>,
+    verifier_ms: Option<u128>,
+    proof_bytes: usize,
+    commitment_bytes: Option<usize>,
+}
 
-    // NIZK mode
-    match args.get(2) {
-        Some(v) if v.clone() == String::from("--nizk") => nizk = true,
-        Some(v) if v.clone() == String::from("--snark") => nizk = false,
-        _ => {
-            nizk=false;
-            eprintln!("{}", usage)
+#[derive(Serialize, Deserialize)]
+enum SavedProof {
+    Nizk {
+        inputs: Vec<[u8; 32]>,
+        proof: NIZK,
+    },
+    Snark {
+        inputs: Vec<[u8; 32]>,
+        comm: ComputationCommitment,
+        proof: SNARK,
+    },
+}
+
+// Every way this CLI can fail, so main() can report a clean message and exit code
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Unsatisfiable,
+    VerificationFailed,
+    Malformed(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(usage) => write!(f, "{}", usage),
+            CliError::Unsatisfiable => {
+                write!(f, "circuit is not satisfied by the given witness and inputs")
+            }
+            CliError::VerificationFailed => write!(f, "proof verification failed"),
+            CliError::Malformed(msg) => write!(f, "malformed input: {}", msg),
         }
     }
+}
 
-    let circuitfn = args.get(3).unwrap();
-    let inputsfn = args.get(4).unwrap();
-    let witnessfn = args.get(5).unwrap();
-
-    let mut fh = File::open(inputsfn).unwrap();
-    let mut bufh = Vec::new();
-    fh.read_to_end(&mut bufh).unwrap();
-    let mut fcs = File::open(circuitfn).unwrap();
-    let mut bufcs = Vec::new();
-    fcs.read_to_end(&mut bufcs).unwrap();
-    let mut fw = File::open(witnessfn).unwrap();
-    let mut bufw = Vec::new();
-    fw.read_to_end(&mut bufw).unwrap();
-
-
-    // Initialize R1csReader
-    let reader = R1csReader::new(&mut bufh, &mut bufcs, &mut bufw);
-    let r1cs = R1cs::from(reader);
-
-    // We will encode the above constraints into three matrices, where
-    // the coefficients in the matrix are in the little-endian byte order
-    let mut A: Vec<(usize, usize, [u8; 32])> = Vec::new();
-    let mut B: Vec<(usize, usize, [u8; 32])> = Vec::new();
-    let mut C: Vec<(usize, usize, [u8; 32])> = Vec::new();
-
-    let inst = r1cs.instance(&mut A, &mut B, &mut C);
-    let assignment_inputs = r1cs.inputs_assignment();
-    let assignment_vars = r1cs.vars_assignment();
-
-    // Check if instance is satisfiable
-    let res = inst.is_sat(&assignment_vars, &assignment_inputs);
-    match res {
-        Ok(res) =>
-            if !res {
-                std::panic!("Circuit should be satisfied by assignments");
-            }
-        Err(e) => std::panic!(e)
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 1,
+            CliError::Unsatisfiable => 2,
+            CliError::VerificationFailed => 3,
+            CliError::Malformed(_) => 4,
+        }
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(e: std::io::Error) -> Self {
+        CliError::Malformed(e.to_string())
+    }
+}
+
+impl From<Box<bincode::ErrorKind>> for CliError {
+    fn from(e: Box<bincode::ErrorKind>) -> Self {
+        CliError::Malformed(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for CliError {
+    fn from(e: serde_json::Error) -> Self {
+        CliError::Malformed(e.to_string())
+    }
+}
+
+// Reads a whole message stream from path, or from stdin when path is "-"
+fn read_file(path: &str) -> Result<Vec<u8>, CliError> {
+    let mut buf = Vec::new();
+    if path == "-" {
+        std::io::stdin().read_to_end(&mut buf)?;
+    } else {
+        File::open(path)?.read_to_end(&mut buf)?;
+    }
+    Ok(buf)
+}
+
+fn parse_mode(arg: Option<&String>, usage: &str) -> Result<bool, CliError> {
+    match arg.map(String::as_str) {
+        Some("--nizk") => Ok(true),
+        Some("--snark") => Ok(false),
+        _ => Err(CliError::Usage(usage.to_string())),
+    }
+}
+
+fn positional<'a>(arg: Option<&'a String>, usage: &str) -> Result<&'a String, CliError> {
+    arg.ok_or_else(|| CliError::Usage(usage.to_string()))
+}
+
+// Pulls a boolean --flag out of the argument list, wherever it is
+fn strip_flag(args: &[String], flag: &str) -> (Vec<String>, bool) {
+    let present = args.iter().any(|a| a == flag);
+    (args.iter().filter(|a| a.as_str() != flag).cloned().collect(), present)
+}
+
+// Pulls a --flag <value> pair out of the argument list, wherever it is
+fn take_value_flag(args: &[String], flag: &str) -> (Vec<String>, Option<String>) {
+    let mut value = None;
+    let mut rest = Vec::with_capacity(args.len());
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == flag && i + 1 < args.len() {
+            value = Some(args[i + 1].clone());
+            i += 2;
+        } else {
+            rest.push(args[i].clone());
+            i += 1;
+        }
+    }
+    (rest, value)
+}
+
+fn prove(args: &[String], usage: &str) -> Result<(), CliError> {
+    let (args, reduce) = strip_flag(args, "--reduce");
+    let (args, json) = strip_flag(&args, "--json");
+    let (args, out) = take_value_flag(&args, "--out");
+    let (args, label) = take_value_flag(&args, "--label");
+    let args = &args[..];
+    let outfn = out.unwrap_or_else(|| "proof.bin".to_string());
+    let label = label.map(String::into_bytes);
+
+    let nizk = parse_mode(args.get(2), usage)?;
+    let circuitfn = positional(args.get(3), usage)?;
+    let inputsfn = positional(args.get(4), usage)?;
+    let witnessfn = positional(args.get(5), usage)?;
+
+    let mut bufh = read_file(inputsfn)?;
+    let mut bufcs = read_file(circuitfn)?;
+    let mut bufw = read_file(witnessfn)?;
+    let reader = R1csReader::new(&mut bufh, &mut bufcs, &mut bufw).map_err(CliError::Malformed)?;
+    let r1cs = R1cs::new(reader, reduce).map_err(CliError::Malformed)?;
+
+    let mut a: Vec<(usize, usize, [u8; 32])> = Vec::new();
+    let mut b: Vec<(usize, usize, [u8; 32])> = Vec::new();
+    let mut c: Vec<(usize, usize, [u8; 32])> = Vec::new();
+
+    let inst = r1cs.instance(&mut a, &mut b, &mut c).map_err(CliError::Malformed)?;
+    let assignment_inputs = r1cs.inputs_assignment().map_err(CliError::Malformed)?;
+    let assignment_vars = r1cs.vars_assignment().map_err(CliError::Malformed)?;
+
+    match inst.is_sat(&assignment_vars, &assignment_inputs) {
+        Ok(true) => {}
+        Ok(false) => return Err(CliError::Unsatisfiable),
+        Err(e) => return Err(CliError::Malformed(format!("{:?}", e))),
     }
 
-    // Start timer for prover
+    let inputs: Vec<[u8; 32]> = r1cs.public_inputs().map_err(CliError::Malformed)?;
     let prover = Instant::now();
 
-    if nizk {
+    let (saved, commitment_bytes) = if nizk {
         let gens = r1cs.nizk_public_params();
-
-        // produce a proof of satisfiability
         let mut prover_transcript = Transcript::new(b"nizk_example");
+        r1cs.bind_transcript(&mut prover_transcript, &a, &b, &c, &inputs, None, label.as_deref()).map_err(CliError::Malformed)?;
         let proof = NIZK::prove(
             &inst,
             assignment_vars,
@@ -83,37 +186,14 @@ fn main() {
             &gens,
             &mut prover_transcript,
         );
-        let prover_ms = prover.elapsed().as_millis();
-        let innerproof = &proof.r1cs_sat_proof;
-        let proof_len = bincode::serialize(innerproof).unwrap().len();
-        let comm_len = bincode::serialize(&innerproof.comm_vars).unwrap().len();
-
-        let verifier = Instant::now();
-        match args.get(1).unwrap().as_str() {
-            "prove" => {
-                eprintln!("Proof length (KB): {}", proof_len as f32 / 1000.0);
-            },
-            "verify" => {
-                let mut verifier_transcript = Transcript::new(b"nizk_example");
-                assert!(proof
-                    .verify(&inst, &assignment_inputs, &mut verifier_transcript, &gens)
-                    .is_ok());
-                let verifier_ms = verifier.elapsed().as_millis();
-                eprintln!("Test: {}", circuitfn);
-                eprintln!("Prover runtime (ms): {}", prover_ms);
-                eprintln!("Verifier runtime (ms): {}", verifier_ms);
-                eprintln!("Proof length (KB): {}", proof_len as f32 / 1000.0);
-            }
-            _ => eprintln!("{}", usage),
-        }
+        eprintln!("Prover runtime (ms): {}", prover.elapsed().as_millis());
+        (SavedProof::Nizk { inputs, proof }, None)
     } else {
-
         let gens = r1cs.snark_public_params();
-        // create a commitment to the R1CS instance
         let (comm, decomm) = SNARK::encode(&inst, &gens);
-
-        // produce a proof of satisfiability
+        let comm_bytes = bincode::serialize(&comm)?;
         let mut prover_transcript = Transcript::new(b"snark_example");
+        r1cs.bind_transcript(&mut prover_transcript, &a, &b, &c, &inputs, Some(&comm_bytes), label.as_deref()).map_err(CliError::Malformed)?;
         let proof = SNARK::prove(
             &inst,
             &decomm,
@@ -122,22 +202,183 @@ fn main() {
             &gens,
             &mut prover_transcript,
         );
-        eprintln!("Circuit: {}", circuitfn);
-        eprintln!("Prover: {}ms", prover.elapsed().as_millis());
-        let verifier = Instant::now();
-        match args.get(1).unwrap().as_str() {
-            "prove" => {
-                eprintln!("Prover: {}ms", prover.elapsed().as_millis());
-            },
-            "verify" => {
-                let mut verifier_transcript = Transcript::new(b"snark_example");
-                assert!(proof
-                    .verify(&comm, &assignment_inputs, &mut verifier_transcript, &gens)
-                    .is_ok());
-                eprintln!("Verifier: {}ms", verifier.elapsed().as_millis());
-                eprintln!("SNARK proof verification successful");
-            }
-            _ => eprintln!("{}", usage),
+        eprintln!("Prover runtime (ms): {}", prover.elapsed().as_millis());
+        let commitment_bytes = comm_bytes.len();
+        (SavedProof::Snark { inputs, comm, proof }, Some(commitment_bytes))
+    };
+    let prover_ms = prover.elapsed().as_millis();
+
+    let bytes = bincode::serialize(&saved)?;
+    File::create(&outfn)?.write_all(&bytes)?;
+    eprintln!("Proof written to {} ({} bytes)", outfn, bytes.len());
+
+    if json {
+        let metrics = Metrics {
+            mode: if nizk { "nizk" } else { "snark" },
+            circuit: circuitfn.clone(),
+            num_cons: r1cs.num_cons(),
+            num_vars: r1cs.num_vars(),
+            num_inputs: r1cs.num_inputs(),
+            prover_ms: Some(prover_ms),
+            verifier_ms: None,
+            proof_bytes: bytes.len(),
+            commitment_bytes,
+        };
+        println!("{}", serde_json::to_string(&metrics)?);
+    }
+    Ok(())
+}
+
+fn verify(args: &[String], usage: &str) -> Result<(), CliError> {
+    let (args, reduce) = strip_flag(args, "--reduce");
+    let (args, json) = strip_flag(&args, "--json");
+    let (args, label) = take_value_flag(&args, "--label");
+    let args = &args[..];
+    let label = label.map(String::into_bytes);
+
+    let nizk = parse_mode(args.get(2), usage)?;
+    let prooffn = positional(args.get(3), usage)?;
+    let circuitfn = positional(args.get(4), usage)?;
+    let inputsfn = positional(args.get(5), usage)?;
+
+    let proof_bytes_on_disk = read_file(prooffn)?;
+    let saved: SavedProof = bincode::deserialize(&proof_bytes_on_disk)?;
+
+    let mut bufh = read_file(inputsfn)?;
+    let mut bufcs = read_file(circuitfn)?;
+    let reader = R1csReader::new_public(&mut bufh, &mut bufcs).map_err(CliError::Malformed)?;
+    let r1cs = R1cs::new(reader, reduce).map_err(CliError::Malformed)?;
+
+    let mut a: Vec<(usize, usize, [u8; 32])> = Vec::new();
+    let mut b: Vec<(usize, usize, [u8; 32])> = Vec::new();
+    let mut c: Vec<(usize, usize, [u8; 32])> = Vec::new();
+    let inst = r1cs.instance(&mut a, &mut b, &mut c).map_err(CliError::Malformed)?;
+    // Use the verifier's own inputs.zkif, not the inputs embedded in proof.bin
+    let inputs = r1cs.public_inputs().map_err(CliError::Malformed)?;
+    let assignment_inputs = InputsAssignment::new(&inputs)
+        .map_err(|e| CliError::Malformed(format!("{:?}", e)))?;
+
+    let verifier = Instant::now();
+    let (ok, commitment_bytes) = match (nizk, &saved) {
+        (true, SavedProof::Nizk { proof, .. }) => {
+            let gens = r1cs.nizk_public_params();
+            let mut verifier_transcript = Transcript::new(b"nizk_example");
+            r1cs.bind_transcript(&mut verifier_transcript, &a, &b, &c, &inputs, None, label.as_deref()).map_err(CliError::Malformed)?;
+            let ok = proof
+                .verify(&inst, &assignment_inputs, &mut verifier_transcript, &gens)
+                .is_ok();
+            (ok, None)
+        }
+        (false, SavedProof::Snark { proof, .. }) => {
+            let gens = r1cs.snark_public_params();
+            // Recompute comm ourselves; SNARK::encode is deterministic, so trusting
+            // proof.bin's comm would let a forged proof target the wrong instance.
+            let (comm, _) = SNARK::encode(&inst, &gens);
+            let comm_bytes = bincode::serialize(&comm)?;
+            let mut verifier_transcript = Transcript::new(b"snark_example");
+            r1cs.bind_transcript(&mut verifier_transcript, &a, &b, &c, &inputs, Some(&comm_bytes), label.as_deref()).map_err(CliError::Malformed)?;
+            let ok = proof
+                .verify(&comm, &assignment_inputs, &mut verifier_transcript, &gens)
+                .is_ok();
+            (ok, Some(comm_bytes.len()))
+        }
+        _ => {
+            return Err(CliError::Malformed(
+                "proof file does not match the requested --nizk/--snark mode".to_string(),
+            ))
         }
+    };
+
+    if !ok {
+        return Err(CliError::VerificationFailed);
+    }
+    let verifier_ms = verifier.elapsed().as_millis();
+    eprintln!("Verifier runtime (ms): {}", verifier_ms);
+    eprintln!("Proof verification successful");
+
+    if json {
+        let metrics = Metrics {
+            mode: if nizk { "nizk" } else { "snark" },
+            circuit: circuitfn.clone(),
+            num_cons: r1cs.num_cons(),
+            num_vars: r1cs.num_vars(),
+            num_inputs: r1cs.num_inputs(),
+            prover_ms: None,
+            verifier_ms: Some(verifier_ms),
+            proof_bytes: proof_bytes_on_disk.len(),
+            commitment_bytes,
+        };
+        println!("{}", serde_json::to_string(&metrics)?);
+    }
+    Ok(())
+}
+
+fn run(args: &[String], usage: &str) -> Result<(), CliError> {
+    match args.get(1).map(String::as_str) {
+        Some("prove") => prove(args, usage),
+        Some("verify") => verify(args, usage),
+        _ => Err(CliError::Usage(usage.to_string())),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let usage = format!(
+        "{0} prove [--nizk|--snark] [--reduce] [--label <bytes>] [--json] <circuit.zkif> <inputs.zkif> <witness.zkif> [--out <proof.bin>]\n{0} verify [--nizk|--snark] [--reduce] [--label <bytes>] [--json] <proof.bin> <circuit.zkif> <inputs.zkif>",
+        args.get(0).unwrap()
+    );
+
+    if let Err(e) = run(&args, &usage) {
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+
+    #[test]
+    fn exit_codes_match_the_documented_contract() {
+        assert_eq!(CliError::Usage(String::new()).exit_code(), 1);
+        assert_eq!(CliError::Unsatisfiable.exit_code(), 2);
+        assert_eq!(CliError::VerificationFailed.exit_code(), 3);
+        assert_eq!(CliError::Malformed(String::new()).exit_code(), 4);
+    }
+
+    #[test]
+    fn run_with_an_unknown_command_is_a_usage_error() {
+        let args: Vec<String> = vec!["spartan-zkinterface".to_string(), "bogus".to_string()];
+        match run(&args, "usage") {
+            Err(CliError::Usage(msg)) => assert_eq!(msg, "usage"),
+            other => panic!("expected Usage error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn run_prove_reports_malformed_for_a_non_zkif_circuit_file() {
+        let dir = std::env::temp_dir();
+        let circuit_path = dir.join("spartan_zkinterface_test_corrupt_circuit.zkif");
+        std::fs::File::create(&circuit_path)
+            .unwrap()
+            .write_all(b"not a zkinterface stream")
+            .unwrap();
+
+        let args: Vec<String> = vec![
+            "spartan-zkinterface".to_string(),
+            "prove".to_string(),
+            "--nizk".to_string(),
+            circuit_path.to_str().unwrap().to_string(),
+            circuit_path.to_str().unwrap().to_string(),
+            circuit_path.to_str().unwrap().to_string(),
+        ];
+
+        match run(&args, "usage") {
+            Err(CliError::Malformed(_)) => {}
+            other => panic!("expected Malformed error, got {:?}", other),
+        }
+
+        let _ = std::fs::remove_file(&circuit_path);
     }
 }